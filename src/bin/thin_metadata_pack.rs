@@ -0,0 +1,95 @@
+extern crate clap;
+extern crate thinp;
+
+use clap::{App, Arg};
+use std::process;
+
+fn main() {
+    let parser = App::new("thin_metadata_pack")
+	.version("0.8.5")	// FIXME: use actual version
+        .about("Produces a compressed file of thin metadata.  Only packs metadata blocks that are actually used.")
+        .arg(Arg::with_name("INPUT")
+            .help("Specify thinp metadata binary device/file")
+            .required(true)
+            .short("i")
+            .value_name("DEV")
+            .takes_value(true))
+        .arg(Arg::with_name("OUTPUT")
+            .help("Specify packed output file")
+            .required(true)
+            .short("o")
+            .value_name("FILE")
+            .takes_value(true))
+        .arg(Arg::with_name("PARITY_SHARDS")
+            .help("Add this many Reed-Solomon parity shards per stripe, so localized corruption of the pack file can be repaired")
+            .short("m")
+            .long("parity-shards")
+            .value_name("NUM")
+            .default_value("0")
+            .takes_value(true))
+        .arg(Arg::with_name("COMPRESSOR")
+            .help("Compression backend used for each chunk")
+            .short("C")
+            .long("compressor")
+            .value_name("CODEC")
+            .possible_values(&["zlib", "zstd"])
+            .default_value("zlib")
+            .takes_value(true))
+        .arg(Arg::with_name("LEVEL")
+            .help("Compression level passed to the chosen compressor")
+            .short("L")
+            .long("level")
+            .value_name("NUM")
+            .default_value("6")
+            .takes_value(true));
+
+    let matches = parser.get_matches();
+    let input_file = matches.value_of("INPUT").unwrap();
+    let output_file = matches.value_of("OUTPUT").unwrap();
+    let nr_parity_shards = matches
+        .value_of("PARITY_SHARDS")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|_| {
+            eprintln!("Couldn't parse --parity-shards");
+            process::exit(1);
+        });
+
+    let codec = match matches.value_of("COMPRESSOR").unwrap() {
+        "zlib" => thinp::pack::pack::CODEC_ZLIB,
+        "zstd" => thinp::pack::pack::CODEC_ZSTD,
+        _ => unreachable!("validated by possible_values"),
+    };
+    let level = matches
+        .value_of("LEVEL")
+        .unwrap()
+        .parse::<i32>()
+        .unwrap_or_else(|_| {
+            eprintln!("Couldn't parse --level");
+            process::exit(1);
+        });
+
+    // "-" means stdin/stdout, so the device can be captured and shipped
+    // to a remote host without ever staging a file: neither side of a
+    // pipe or socket supports the seeking `pack` relies on, so route to
+    // the single-pass streaming encoder instead.
+    if input_file == "-" || output_file == "-" {
+        if nr_parity_shards > 0 {
+            eprintln!("--parity-shards is not supported when streaming over - (stdin/stdout)");
+            process::exit(1);
+        }
+        if let Err(reason) = thinp::pack::pack::pack_stream(&input_file, &output_file, codec, level)
+        {
+            println!("Application error: {}", reason);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Err(reason) =
+        thinp::pack::pack::pack(&input_file, &output_file, nr_parity_shards, codec, level)
+    {
+        println!("Application error: {}", reason);
+        process::exit(1);
+    }
+}
@@ -16,17 +16,70 @@ fn main() {
             .takes_value(true))
         .arg(Arg::with_name("OUTPUT")
             .help("Specify packed output file")
-            .required(true)
+            .required_unless("VERIFY")
             .short("o")
             .value_name("FILE")
-            .takes_value(true));
-
+            .takes_value(true))
+        .arg(Arg::with_name("PARITY_SHARDS")
+            .help("Assert the pack file was created with this many Reed-Solomon parity shards per stripe")
+            .short("m")
+            .long("parity-shards")
+            .value_name("NUM")
+            .takes_value(true))
+        .arg(Arg::with_name("VERIFY")
+            .help("Check the pack file's integrity digest instead of unpacking it")
+            .long("verify")
+            .takes_value(false))
+        .arg(Arg::with_name("SALVAGE")
+            .help("Recover as many blocks as possible from a damaged pack file instead of aborting on the first bad chunk")
+            .short("s")
+            .long("salvage")
+            .takes_value(false));
 
     let matches = parser.get_matches();
     let input_file = matches.value_of("INPUT").unwrap();
+
+    if matches.is_present("VERIFY") {
+        if let Err(reason) = thinp::pack::pack::verify(&input_file) {
+            println!("Application error: {}", reason);
+            process::exit(1);
+        }
+        return;
+    }
+
     let output_file = matches.value_of("OUTPUT").unwrap();
+    let expected_parity_shards = matches.value_of("PARITY_SHARDS").map(|s| {
+        s.parse::<u64>().unwrap_or_else(|_| {
+            eprintln!("Couldn't parse --parity-shards");
+            process::exit(1);
+        })
+    });
+
+    let salvage = matches.is_present("SALVAGE");
+
+    // "-" means stdin, so a pack file can be received straight off a
+    // pipe or socket (e.g. piped in over ssh) without staging it on
+    // disk first; the output still needs to be a seekable path (the
+    // target block device).
+    if input_file == "-" {
+        if expected_parity_shards.is_some() {
+            eprintln!("--parity-shards is not supported when streaming over - (stdin)");
+            process::exit(1);
+        }
+        if salvage {
+            eprintln!("--salvage is not supported when streaming over - (stdin)");
+            process::exit(1);
+        }
+        if let Err(reason) = thinp::pack::pack::unpack_stream(&input_file, &output_file) {
+            println!("Application error: {}", reason);
+            process::exit(1);
+        }
+        return;
+    }
 
-    if let Err(reason) = thinp::pack::pack::unpack(&input_file, &output_file) {
+    if let Err(reason) =
+        thinp::pack::pack::unpack(&input_file, &output_file, expected_parity_shards, salvage)
+    {
         println!("Application error: {}", reason);
         process::exit(1);
     }
@@ -4,6 +4,7 @@ use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
 
 use std::os::unix::fs::OpenOptionsExt;
 use std::{
+    collections::HashMap,
     error::Error,
     fs::OpenOptions,
     io,
@@ -22,12 +23,54 @@ use crate::pack::node_encode::*;
 
 const BLOCK_SIZE: u64 = 4096;
 const MAGIC: u64 = 0xa537a0aa6309ef77;
-const PACK_VERSION: u64 = 3;
+const PACK_VERSION: u64 = 8;
 const SUPERBLOCK_CSUM_XOR: u32 = 160774;
 const BITMAP_CSUM_XOR: u32 = 240779;
 const INDEX_CSUM_XOR: u32 = 160478;
 const BTREE_CSUM_XOR: u32 = 121107;
 
+// A corrupt length prefix shouldn't be able to make unpack allocate a
+// multi-gigabyte buffer before it even reads the data to validate it.
+const MAX_CHUNK_LEN: u64 = 1024 * 1024 * 1024;
+
+// Default number of data shards per FEC stripe, and the size of each
+// shard.  Only `-m/--parity-shards` is user-facing; `k` and the shard
+// size just need to be consistent between pack and unpack, which the
+// header guarantees.
+const FEC_K: usize = 8;
+const FEC_SHARD_SIZE: usize = 1024 * 1024;
+
+// Tags written ahead of each block's record in the chunk stream so
+// `decode_worker` knows whether it's looking at a fully encoded block
+// or a back-reference to a block it has seen already.
+const RECORD_PACKED: u8 = 0;
+const RECORD_REFERENCE: u8 = 1;
+
+// Each chunk carries its own codec tag, so a pack file can mix chunks
+// compressed with different codecs (e.g. an older zlib-only file, or a
+// future codec) and `unpack` still knows how to read every one of them.
+pub const CODEC_ZLIB: u8 = 0;
+pub const CODEC_ZSTD: u8 = 1;
+
+fn codec_name(codec: u8) -> &'static str {
+    match codec {
+        CODEC_ZLIB => "zlib",
+        CODEC_ZSTD => "zstd",
+        _ => "unknown",
+    }
+}
+
+type BlockDigest = [u8; 32];
+
+// Shared across all `crunch` workers so a block that repeats anywhere
+// in the device (e.g. the zeroed regions or nodes shared between thin
+// and its snapshots) is only compressed once.
+type DedupTable = Arc<Mutex<HashMap<BlockDigest, u64>>>;
+
+fn hash_block(buf: &[u8]) -> BlockDigest {
+    blake3::hash(buf).into()
+}
+
 fn shuffle<T>(v: &mut Vec<T>) {
     let mut rng = rand::thread_rng();
     v.shuffle(&mut rng);
@@ -66,7 +109,78 @@ fn mk_chunk_vecs(nr_blocks: u64, nr_jobs: u64) -> Vec<Vec<(u64, u64)>> {
     vs
 }
 
-pub fn pack(input_file: &str, output_file: &str) -> Result<(), Box<dyn Error>> {
+// Where a worker's compressed chunks end up.  Plain packs write each
+// chunk straight to the output file as before; when FEC is enabled the
+// chunk stream is staged in memory first, so it can be striped into
+// shards and parity computed once every worker is done.
+//
+// Known limitation: `Staged` buffers the *entire* compressed body for
+// the whole device before `write_fec_body` stripes it, rather than
+// striping incrementally as chunks complete. That's a real memory cost
+// on large metadata devices with `--parity-shards` set; fine for now
+// since thin metadata is small relative to the data it describes, but
+// worth revisiting if that stops being true.
+enum ChunkSink<W> {
+    Direct(Arc<Mutex<W>>),
+    Staged(Arc<Mutex<Vec<u8>>>),
+}
+
+impl<W> Clone for ChunkSink<W> {
+    fn clone(&self) -> Self {
+        match self {
+            ChunkSink::Direct(w) => ChunkSink::Direct(Arc::clone(w)),
+            ChunkSink::Staged(buf) => ChunkSink::Staged(Arc::clone(buf)),
+        }
+    }
+}
+
+impl<W: Write> ChunkSink<W> {
+    // `lo`/`hi` is the block range this chunk was built from, so a
+    // salvage unpack can report precisely which part of the device is
+    // lost if the chunk turns out to be damaged.  `codec` records which
+    // compressor `compressed` was produced with.
+    fn write_chunk(&self, lo: u64, hi: u64, codec: u8, compressed: &[u8]) -> io::Result<()> {
+        match self {
+            ChunkSink::Direct(output) => {
+                let mut output = output.lock().unwrap();
+                output.write_u64::<LittleEndian>(lo)?;
+                output.write_u64::<LittleEndian>(hi)?;
+                output.write_u8(codec)?;
+                output.write_u64::<LittleEndian>(compressed.len() as u64)?;
+                output.write_all(compressed)
+            }
+            ChunkSink::Staged(buf) => {
+                let mut buf = buf.lock().unwrap();
+                buf.write_u64::<LittleEndian>(lo)?;
+                buf.write_u64::<LittleEndian>(hi)?;
+                buf.write_u8(codec)?;
+                buf.write_u64::<LittleEndian>(compressed.len() as u64)?;
+                buf.write_all(compressed)
+            }
+        }
+    }
+}
+
+pub fn pack(
+    input_file: &str,
+    output_file: &str,
+    nr_parity_shards: u64,
+    codec: u8,
+    level: i32,
+) -> Result<(), Box<dyn Error>> {
+    // `fec_parity_matrix` builds each parity row from `(FEC_K + i) as
+    // u8`; once `nr_parity_shards` pushes that past 255 it wraps and
+    // collides with a data-shard index, which reaches `gf_inv(0)` and
+    // panics. Reject that up front instead of letting a panic take down
+    // the whole run.
+    if nr_parity_shards > 0 && nr_parity_shards + FEC_K as u64 > 256 {
+        return Err(format!(
+            "--parity-shards={} is too large: parity-shards + {} (the number of data shards per stripe) must not exceed 256",
+            nr_parity_shards, FEC_K
+        )
+        .into());
+    }
+
     let nr_blocks = get_nr_blocks(&input_file)?;
     let nr_jobs = std::cmp::max(1, std::cmp::min(num_cpus::get() as u64, nr_blocks / 128));
     let chunk_vecs = mk_chunk_vecs(nr_blocks, nr_jobs);
@@ -78,42 +192,103 @@ pub fn pack(input_file: &str, output_file: &str) -> Result<(), Box<dyn Error>> {
         .open(input_file)?;
 
     let output = OpenOptions::new()
-        .read(false)
+        .read(true)
         .write(true)
         .create(true)
         .truncate(true)
         .open(output_file)?;
 
-    write_header(&output, nr_blocks)?;
+    let fec_k = if nr_parity_shards > 0 {
+        FEC_K as u64
+    } else {
+        0
+    };
+    let fec_shard_size = if nr_parity_shards > 0 {
+        FEC_SHARD_SIZE as u64
+    } else {
+        0
+    };
+    write_header(
+        &output,
+        nr_blocks,
+        fec_k,
+        nr_parity_shards,
+        fec_shard_size,
+        codec,
+    )?;
 
     let sync_input = Arc::new(Mutex::new(input));
     let sync_output = Arc::new(Mutex::new(output));
+    let dedup_table: DedupTable = Arc::new(Mutex::new(HashMap::new()));
+    let staged: Arc<Mutex<Vec<u8>>> = Arc::new(Mutex::new(Vec::new()));
+
+    // Plain packs hand each compressed chunk straight to the output
+    // file, same as before.  FEC packs stage the whole chunk stream in
+    // memory instead, so it can be striped into shards once every
+    // worker has finished.
+    let sink = if nr_parity_shards > 0 {
+        ChunkSink::Staged(Arc::clone(&staged))
+    } else {
+        ChunkSink::Direct(Arc::clone(&sync_output))
+    };
 
     let mut threads = Vec::new();
     for job in 0..nr_jobs {
         let sync_input = Arc::clone(&sync_input);
-        let sync_output = Arc::clone(&sync_output);
+        let sink = sink.clone();
+        let dedup_table = Arc::clone(&dedup_table);
         let chunks = chunk_vecs[job as usize].clone();
-        threads.push(spawn(move || crunch(sync_input, sync_output, chunks)));
+        threads.push(spawn(move || {
+            crunch(sync_input, sink, dedup_table, chunks, codec, level)
+        }));
     }
 
     for t in threads {
         t.join().unwrap()?;
     }
+
+    // Every worker's clone of `sink` is gone now that the threads have
+    // joined, but the original `sink` built above is still holding its
+    // own `Arc` clone of `sync_output`/`staged` - drop it so the
+    // `try_unwrap`s below see a single owner.
+    drop(sink);
+
+    let mut output = Arc::try_unwrap(sync_output).unwrap().into_inner().unwrap();
+
+    if nr_parity_shards > 0 {
+        let body = Arc::try_unwrap(staged).unwrap().into_inner().unwrap();
+        write_fec_body(
+            &mut output,
+            &body,
+            FEC_K,
+            nr_parity_shards as usize,
+            FEC_SHARD_SIZE,
+        )?;
+    }
+
+    patch_body_digest(&mut output)?;
+
     Ok(())
 }
 
 fn crunch<R, W>(
     input: Arc<Mutex<R>>,
-    output: Arc<Mutex<W>>,
+    sink: ChunkSink<W>,
+    dedup_table: DedupTable,
     ranges: Vec<(u64, u64)>,
+    codec: u8,
+    level: i32,
 ) -> io::Result<()>
 where
     R: Read + Seek,
     W: Write,
 {
     let mut written = 0u64;
-    let mut z = ZlibEncoder::new(Vec::new(), Compression::default());
+    let mut chunk_lo: Option<u64> = None;
+    let mut chunk_hi = 0u64;
+    // Each chunk's records are buffered uncompressed here, then handed
+    // to the chosen codec as a whole once the chunk is full.
+    let mut raw = Vec::new();
     for (lo, hi) in ranges {
         // We read multiple blocks at once to reduce contention
         // on input.
@@ -126,33 +301,130 @@ where
             let data = &big_data[block_start..(block_start + BLOCK_SIZE as usize)];
             let kind = metadata_block_type(data);
             if kind != BT::UNKNOWN {
-                z.write_u64::<LittleEndian>(b)?;
-                pack_block(&mut z, kind, &data);
+                if chunk_lo.is_none() {
+                    chunk_lo = Some(b);
+                }
+                chunk_hi = b + 1;
+
+                raw.write_u64::<LittleEndian>(b)?;
+
+                let digest = hash_block(data);
+                let earlier = {
+                    let mut table = dedup_table.lock().unwrap();
+                    match table.get(&digest) {
+                        Some(&earlier) => Some(earlier),
+                        None => {
+                            table.insert(digest, b);
+                            None
+                        }
+                    }
+                };
+
+                match earlier {
+                    Some(ref_to) => {
+                        raw.write_u8(RECORD_REFERENCE)?;
+                        raw.write_u64::<LittleEndian>(ref_to)?;
+                    }
+                    None => {
+                        raw.write_u8(RECORD_PACKED)?;
+                        pack_block(&mut raw, kind, &data);
+                    }
+                }
 
                 written += 1;
                 if written == 1024 {
-                    let compressed = z.reset(Vec::new())?;
-
-                    let mut output = output.lock().unwrap();
-                    output.write_u64::<LittleEndian>(compressed.len() as u64)?;
-                    output.write_all(&compressed)?;
+                    let compressed = compress_chunk(codec, level, &raw)?;
+                    sink.write_chunk(chunk_lo.take().unwrap(), chunk_hi, codec, &compressed)?;
+                    raw.clear();
                     written = 0;
                 }
             }
         }
-    }
 
-    if written > 0 {
-        let compressed = z.finish()?;
-        let mut output = output.lock().unwrap();
-        output.write_u64::<LittleEndian>(compressed.len() as u64)?;
-        output.write_all(&compressed)?;
+        // Ranges are shuffled across the device before being handed out
+        // (see `mk_chunk_vecs`), so a later range in this job's list can
+        // start at a lower block number than an earlier one; flushing
+        // here keeps every chunk's (lo, hi) a real contiguous span
+        // instead of one that jumps backwards, which is what salvage
+        // mode assumes when it zero-fills `lo..hi` for a damaged chunk.
+        if written > 0 {
+            let compressed = compress_chunk(codec, level, &raw)?;
+            sink.write_chunk(chunk_lo.take().unwrap(), chunk_hi, codec, &compressed)?;
+            raw.clear();
+            written = 0;
+        }
     }
 
     Ok(())
 }
 
-fn write_header<W>(mut w: W, nr_blocks: u64) -> io::Result<()>
+// Compresses one whole chunk's worth of plain record bytes with the
+// requested codec.
+fn compress_chunk(codec: u8, level: i32, data: &[u8]) -> io::Result<Vec<u8>> {
+    match codec {
+        CODEC_ZLIB => {
+            let mut z = ZlibEncoder::new(Vec::new(), Compression::new(level as u32));
+            z.write_all(data)?;
+            z.finish()
+        }
+        CODEC_ZSTD => zstd::stream::encode_all(data, level),
+        codec => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown compressor {}", codec),
+        )),
+    }
+}
+
+// Inverse of `compress_chunk`, dispatching on the codec tag each chunk
+// carries in the wire format, so a pack file can freely mix codecs.
+fn decompress_chunk(codec: u8, data: &[u8]) -> io::Result<Vec<u8>> {
+    match codec {
+        CODEC_ZLIB => {
+            let mut z = ZlibDecoder::new(data);
+            let mut out = Vec::new();
+            z.read_to_end(&mut out)?;
+            Ok(out)
+        }
+        CODEC_ZSTD => zstd::stream::decode_all(data),
+        codec => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown chunk codec {}, pack file is corrupt", codec),
+        )),
+    }
+}
+
+// Fixed-size header: MAGIC, version, block size, nr_blocks, the three
+// FEC fields, the default codec, then a trailing digest.  `pack` writes
+// zeroes for the digest up front and patches it in once the whole body
+// is known; `HEADER_DIGEST_OFFSET` is where that patch lands.
+const HEADER_DIGEST_OFFSET: u64 = 8 * 8;
+const HEADER_LEN: u64 = HEADER_DIGEST_OFFSET + DIGEST_LEN as u64;
+const DIGEST_LEN: usize = 32;
+
+struct PackHeader {
+    nr_blocks: u64,
+    // fec_m == 0 means the body is the plain length-prefixed chunk
+    // stream; otherwise it's a sequence of FEC stripes with `fec_k`
+    // data shards and `fec_m` parity shards of `fec_shard_size` bytes.
+    fec_k: u64,
+    fec_m: u64,
+    fec_shard_size: u64,
+    // The codec `pack` was asked to use; purely informational, since
+    // every chunk also carries its own codec tag.
+    default_codec: u8,
+    // BLAKE3 digest of the compressed body (everything after the
+    // header), so `--verify` can catch silent truncation or bit-rot.
+    digest: [u8; DIGEST_LEN],
+}
+
+fn write_header<W>(
+    mut w: W,
+    nr_blocks: u64,
+    fec_k: u64,
+    fec_m: u64,
+    fec_shard_size: u64,
+    default_codec: u8,
+) -> io::Result<()>
 where
     W: byteorder::WriteBytesExt,
 {
@@ -160,21 +432,409 @@ where
     w.write_u64::<LittleEndian>(PACK_VERSION)?;
     w.write_u64::<LittleEndian>(4096)?;
     w.write_u64::<LittleEndian>(nr_blocks)?;
+    w.write_u64::<LittleEndian>(fec_k)?;
+    w.write_u64::<LittleEndian>(fec_m)?;
+    w.write_u64::<LittleEndian>(fec_shard_size)?;
+    w.write_u64::<LittleEndian>(default_codec as u64)?;
+    w.write_all(&[0u8; DIGEST_LEN])?;
 
     Ok(())
 }
 
-fn read_header<R>(mut r: R) -> io::Result<u64>
+fn read_header<R>(mut r: R) -> io::Result<PackHeader>
 where
     R: byteorder::ReadBytesExt,
 {
     let magic = r.read_u64::<LittleEndian>()?;
-    assert_eq!(magic, MAGIC);
+    if magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bad magic number, not a thin_metadata_pack file (or it's corrupt)",
+        ));
+    }
     let version = r.read_u64::<LittleEndian>()?;
-    assert_eq!(version, PACK_VERSION);
+    if version != PACK_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "unsupported pack version {} (expected {}), pack file is corrupt or from an incompatible build",
+                version, PACK_VERSION
+            ),
+        ));
+    }
     let block_size = r.read_u64::<LittleEndian>()?;
-    assert_eq!(block_size, 4096);
-    r.read_u64::<LittleEndian>()
+    if block_size != 4096 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "unexpected block size {} in pack header, pack file is corrupt",
+                block_size
+            ),
+        ));
+    }
+    let nr_blocks = r.read_u64::<LittleEndian>()?;
+    let fec_k = r.read_u64::<LittleEndian>()?;
+    let fec_m = r.read_u64::<LittleEndian>()?;
+    let fec_shard_size = r.read_u64::<LittleEndian>()?;
+    let default_codec = r.read_u64::<LittleEndian>()? as u8;
+    let mut digest = [0u8; DIGEST_LEN];
+    r.read_exact(&mut digest)?;
+
+    // `pack` only ever writes one of two shapes: no FEC at all (all three
+    // fields zero), or `FEC_K` data shards of exactly `FEC_SHARD_SIZE`
+    // bytes with some number of parity shards (checked against the same
+    // 256-row-index limit `pack` enforces up front). A corrupt header
+    // could claim anything else, and `read_fec_stripe` would take
+    // `fec_shard_size` on faith and allocate `k + m` shards of that size
+    // per stripe before validating any of it - so reject anything outside
+    // those two shapes here instead of letting garbage ride all the way
+    // down to an allocation or a `gf_invert_matrix` panic.
+    if fec_m == 0 {
+        if fec_k != 0 || fec_shard_size != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "pack header has no parity shards but a non-zero fec_k/fec_shard_size, pack file is corrupt",
+            ));
+        }
+    } else {
+        if fec_k != FEC_K as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "pack header's fec_k ({}) doesn't match this build's FEC_K ({}), pack file is corrupt or from an incompatible build",
+                    fec_k, FEC_K
+                ),
+            ));
+        }
+        if fec_shard_size != FEC_SHARD_SIZE as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "pack header's fec_shard_size ({}) doesn't match this build's FEC_SHARD_SIZE ({}), pack file is corrupt or from an incompatible build",
+                    fec_shard_size, FEC_SHARD_SIZE
+                ),
+            ));
+        }
+        if fec_m + FEC_K as u64 > 256 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "pack header's fec_m ({}) plus FEC_K ({}) exceeds 256, pack file is corrupt",
+                    fec_m, FEC_K
+                ),
+            ));
+        }
+    }
+
+    Ok(PackHeader {
+        nr_blocks,
+        fec_k,
+        fec_m,
+        fec_shard_size,
+        default_codec,
+        digest,
+    })
+}
+
+// Streams everything after the header, hashes it with BLAKE3, and
+// patches the digest back into the reserved header field.  Called once
+// the whole body (plain chunk stream or FEC stripes) has been written.
+fn patch_body_digest<F: Read + Write + Seek>(f: &mut F) -> io::Result<()> {
+    f.seek(io::SeekFrom::Start(HEADER_LEN))?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[0..n]);
+    }
+
+    f.seek(io::SeekFrom::Start(HEADER_DIGEST_OFFSET))?;
+    f.write_all(hasher.finalize().as_bytes())?;
+    Ok(())
+}
+
+fn shard_checksum(buf: &[u8]) -> u32 {
+    crc32c::crc32c(buf)
+}
+
+//
+// ---- Optional Reed-Solomon forward error correction ----
+//
+// When `-m/--parity-shards` is non-zero, the stream of compressed
+// chunks is grouped into fixed-size stripes of `FEC_K` data shards, and
+// `m` parity shards are appended per stripe using a systematic
+// Reed-Solomon code over GF(2^8): the data shards pass straight
+// through (an implicit k*k identity sub-matrix) and each parity shard
+// is a Cauchy-matrix combination of the data shards. Any `k` surviving
+// shards out of `k + m` are enough to reconstruct the rest, because an
+// identity-plus-Cauchy matrix is MDS (every square sub-matrix of it is
+// invertible).
+//
+
+const GF_POLY: u16 = 0x11d;
+
+struct GfTables {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+#[allow(clippy::needless_range_loop)]
+fn gf_tables() -> &'static GfTables {
+    static TABLES: std::sync::OnceLock<GfTables> = std::sync::OnceLock::new();
+    TABLES.get_or_init(|| {
+        let mut exp = [0u8; 512];
+        let mut log = [0u8; 256];
+        let mut x: u16 = 1;
+        for i in 0..255usize {
+            exp[i] = x as u8;
+            log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= GF_POLY;
+            }
+        }
+        for i in 255..512 {
+            exp[i] = exp[i - 255];
+        }
+        GfTables { exp, log }
+    })
+}
+
+fn gf_mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    let t = gf_tables();
+    let sum = t.log[a as usize] as usize + t.log[b as usize] as usize;
+    t.exp[sum]
+}
+
+fn gf_inv(a: u8) -> u8 {
+    assert!(a != 0, "cannot invert zero in GF(256)");
+    let t = gf_tables();
+    t.exp[255 - t.log[a as usize] as usize]
+}
+
+// The m x k parity sub-matrix: row i, column j is 1 / ((k + i) XOR j),
+// which is always defined because the two ranges never overlap.
+fn fec_parity_matrix(k: usize, m: usize) -> Vec<Vec<u8>> {
+    let mut rows = Vec::with_capacity(m);
+    for i in 0..m {
+        let x = (k + i) as u8;
+        let row = (0..k).map(|j| gf_inv(x ^ (j as u8))).collect();
+        rows.push(row);
+    }
+    rows
+}
+
+// Row `r` of the full (k + m) x k generator matrix.
+fn fec_row(r: usize, k: usize, m: usize) -> Vec<u8> {
+    if r < k {
+        let mut row = vec![0u8; k];
+        row[r] = 1;
+        row
+    } else {
+        fec_parity_matrix(k, m).remove(r - k)
+    }
+}
+
+fn fec_encode_stripe(data_shards: &[Vec<u8>], m: usize) -> Vec<Vec<u8>> {
+    let k = data_shards.len();
+    let shard_len = data_shards[0].len();
+    let matrix = fec_parity_matrix(k, m);
+
+    let mut parity = vec![vec![0u8; shard_len]; m];
+    for (i, row) in matrix.iter().enumerate() {
+        for (j, &coeff) in row.iter().enumerate() {
+            if coeff == 0 {
+                continue;
+            }
+            for pos in 0..shard_len {
+                parity[i][pos] ^= gf_mul(coeff, data_shards[j][pos]);
+            }
+        }
+    }
+    parity
+}
+
+// Gauss-Jordan inversion of a k x k matrix over GF(256).
+fn gf_invert_matrix(mut m: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    let n = m.len();
+    let mut inv: Vec<Vec<u8>> = (0..n)
+        .map(|i| {
+            let mut row = vec![0u8; n];
+            row[i] = 1;
+            row
+        })
+        .collect();
+
+    for col in 0..n {
+        let mut pivot = col;
+        while pivot < n && m[pivot][col] == 0 {
+            pivot += 1;
+        }
+        assert!(pivot < n, "singular FEC matrix");
+        m.swap(col, pivot);
+        inv.swap(col, pivot);
+
+        let inv_pivot = gf_inv(m[col][col]);
+        for x in m[col].iter_mut() {
+            *x = gf_mul(*x, inv_pivot);
+        }
+        for x in inv[col].iter_mut() {
+            *x = gf_mul(*x, inv_pivot);
+        }
+
+        for row in 0..n {
+            if row == col {
+                continue;
+            }
+            let factor = m[row][col];
+            if factor == 0 {
+                continue;
+            }
+            for c in 0..n {
+                m[row][c] ^= gf_mul(factor, m[col][c]);
+                inv[row][c] ^= gf_mul(factor, inv[col][c]);
+            }
+        }
+    }
+
+    inv
+}
+
+// Reconstructs the `k` data shards of a stripe from whichever shards
+// survived, provided at least `k` of the `k + m` are intact.
+fn fec_reconstruct_stripe(shards: &[Option<Vec<u8>>], k: usize, m: usize) -> Option<Vec<Vec<u8>>> {
+    let good: Vec<usize> = (0..k + m).filter(|&i| shards[i].is_some()).collect();
+    if good.len() < k {
+        return None;
+    }
+    let chosen = &good[0..k];
+
+    let rows: Vec<Vec<u8>> = chosen.iter().map(|&r| fec_row(r, k, m)).collect();
+    let inv = gf_invert_matrix(rows);
+
+    let shard_len = shards[chosen[0]].as_ref().unwrap().len();
+    let mut data_shards = vec![vec![0u8; shard_len]; k];
+    for (out, out_row) in data_shards.iter_mut().enumerate() {
+        for (col, &r) in chosen.iter().enumerate() {
+            let coeff = inv[out][col];
+            if coeff == 0 {
+                continue;
+            }
+            let src = shards[r].as_ref().unwrap();
+            for pos in 0..shard_len {
+                out_row[pos] ^= gf_mul(coeff, src[pos]);
+            }
+        }
+    }
+    Some(data_shards)
+}
+
+fn write_fec_body<W: Write>(
+    w: &mut W,
+    data: &[u8],
+    k: usize,
+    m: usize,
+    shard_size: usize,
+) -> io::Result<()> {
+    let stripe_bytes = k * shard_size;
+    let mut offset = 0usize;
+
+    // An empty body still gets one (all-zero) stripe, so unpack always
+    // has at least one descriptor to read.
+    loop {
+        let remaining = data.len() - offset;
+        let take = remaining.min(stripe_bytes);
+
+        let mut data_shards = Vec::with_capacity(k);
+        for i in 0..k {
+            let start = offset + i * shard_size;
+            let end = (offset + (i + 1) * shard_size).min(data.len());
+            let mut shard = vec![0u8; shard_size];
+            if start < end {
+                shard[0..end - start].copy_from_slice(&data[start..end]);
+            }
+            data_shards.push(shard);
+        }
+
+        let parity_shards = fec_encode_stripe(&data_shards, m);
+
+        w.write_u64::<LittleEndian>(take as u64)?;
+        for shard in data_shards.iter().chain(parity_shards.iter()) {
+            w.write_u32::<LittleEndian>(shard_checksum(shard))?;
+            w.write_all(shard)?;
+        }
+
+        offset += take;
+        if offset >= data.len() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// Reads one stripe's worth of shards and returns the reconstructed (or
+// already-intact) data bytes. If more than `m` shards in the stripe
+// were damaged it returns an error, or in salvage mode (`report` is
+// set) `take` zero bytes.
+fn read_fec_stripe<R: Read>(
+    r: &mut R,
+    k: usize,
+    m: usize,
+    shard_size: usize,
+    report: Option<&Arc<Mutex<SalvageReport>>>,
+) -> io::Result<Vec<u8>> {
+    let take = r.read_u64::<LittleEndian>()? as usize;
+
+    let mut shards: Vec<Option<Vec<u8>>> = Vec::with_capacity(k + m);
+    for _ in 0..k + m {
+        let crc = r.read_u32::<LittleEndian>()?;
+        let mut shard = vec![0u8; shard_size];
+        r.read_exact(&mut shard)?;
+        shards.push(if shard_checksum(&shard) == crc {
+            Some(shard)
+        } else {
+            None
+        });
+    }
+
+    let data_shards = if shards[0..k].iter().all(|s| s.is_some()) {
+        shards.drain(0..k).map(|s| s.unwrap()).collect()
+    } else {
+        match fec_reconstruct_stripe(&shards, k, m) {
+            Some(data_shards) => data_shards,
+            None => {
+                // Outside salvage mode an unrecoverable stripe is fatal,
+                // same as before.
+                let report = report.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "FEC stripe unrecoverable: fewer than k shards survived",
+                    )
+                })?;
+
+                eprintln!(
+                    "warning: FEC stripe unrecoverable (fewer than {} of {} shards survived), zero-filling",
+                    k, k + m
+                );
+                report.lock().unwrap().fec_stripes_lost += 1;
+                return Ok(vec![0u8; take]);
+            }
+        }
+    };
+
+    let mut body = Vec::with_capacity(k * shard_size);
+    for shard in data_shards {
+        body.extend_from_slice(&shard);
+    }
+    body.truncate(take);
+    Ok(body)
 }
 
 fn get_nr_blocks(path: &str) -> io::Result<u64> {
@@ -273,44 +933,253 @@ where
     Ok(())
 }
 
-fn decode_worker<W>(rx: Receiver<Vec<u8>>, w: Arc<Mutex<W>>) -> io::Result<()>
+// Tallies what a salvage unpack managed to recover, so an operator can
+// see exactly which parts of the reconstructed device are trustworthy.
+#[derive(Default, Debug)]
+struct SalvageReport {
+    chunks_processed: u64,
+    chunks_lost: u64,
+    blocks_recovered: u64,
+    blocks_zeroed: u64,
+    fec_stripes_lost: u64,
+    lost_ranges: Vec<(u64, u64)>,
+}
+
+impl SalvageReport {
+    fn print_summary(&self) {
+        println!(
+            "salvage summary: {} chunks processed, {} chunks lost, {} blocks recovered, {} blocks zero-filled, {} FEC stripes unrecoverable",
+            self.chunks_processed,
+            self.chunks_lost,
+            self.blocks_recovered,
+            self.blocks_zeroed,
+            self.fec_stripes_lost
+        );
+        if !self.lost_ranges.is_empty() {
+            println!("lost block ranges (zero-filled, not trustworthy):");
+            for (lo, hi) in &self.lost_ranges {
+                println!("  [{}, {})", lo, hi);
+            }
+        }
+    }
+}
+
+// One compressed chunk in flight between `dispatch_chunks` and a
+// `decode_worker`: the block range it covers, its codec tag, and the
+// compressed bytes themselves.
+type ChunkMsg = (u64, u64, u8, Vec<u8>);
+
+// Decodes one compressed chunk in isolation, so a salvage unpack can
+// discard the whole chunk on any error without disturbing blocks
+// already recovered from other chunks.
+type DecodedChunk = (Vec<(u64, Vec<u8>)>, Vec<(u64, u64)>);
+
+fn decode_chunk(codec: u8, bytes: &[u8]) -> io::Result<DecodedChunk> {
+    let raw = decompress_chunk(codec, bytes)?;
+    let mut r = Cursor::new(raw);
+    let mut blocks = Vec::new();
+    let mut refs = Vec::new();
+
+    loop {
+        let b = match r.read_u64::<LittleEndian>() {
+            Ok(b) => b,
+            // A clean run-out of the decompressed stream, i.e. the
+            // normal end of this chunk.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        match r.read_u8()? {
+            RECORD_PACKED => {
+                let block = crate::pack::vm::unpack(&mut r, BLOCK_SIZE as usize)?;
+                if metadata_block_type(&block[0..]) == BT::UNKNOWN {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "decoded block has an unexpected type, chunk is corrupt",
+                    ));
+                }
+                blocks.push((b, block));
+            }
+            RECORD_REFERENCE => {
+                let ref_to = r.read_u64::<LittleEndian>()?;
+                // The target block may still be in flight on another
+                // worker, so references are resolved in a second pass
+                // once every self-contained block has been written.
+                refs.push((b, ref_to));
+            }
+            tag => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown record tag {}", tag),
+                ))
+            }
+        }
+    }
+
+    Ok((blocks, refs))
+}
+
+fn decode_worker<W>(
+    rx: Receiver<ChunkMsg>,
+    w: Arc<Mutex<W>>,
+    pending_refs: Arc<Mutex<Vec<(u64, u64)>>>,
+    report: Option<Arc<Mutex<SalvageReport>>>,
+) -> io::Result<()>
 where
     W: Write + Seek,
 {
     let mut blocks = Vec::new();
 
-    while let Ok(bytes) = rx.recv() {
-        let mut z = ZlibDecoder::new(&bytes[0..]);
+    while let Ok((lo, hi, codec, bytes)) = rx.recv() {
+        match decode_chunk(codec, &bytes) {
+            Ok((mut decoded, refs)) => {
+                if let Some(report) = &report {
+                    let mut report = report.lock().unwrap();
+                    report.chunks_processed += 1;
+                    // A reference's own chunk decoding cleanly doesn't
+                    // mean its *target* block is trustworthy - that
+                    // target may live in a chunk some other worker
+                    // hasn't gotten to yet, and which may turn out to
+                    // be damaged. Self-contained blocks are counted
+                    // here; references are tallied once every chunk has
+                    // been decoded and `resolve_references` knows which
+                    // targets actually survived (see `unpack`).
+                    report.blocks_recovered += decoded.len() as u64;
+                }
+                blocks.append(&mut decoded);
+                pending_refs.lock().unwrap().extend(refs);
+            }
+            Err(e) => {
+                // Outside salvage mode a damaged chunk is fatal, same as
+                // before.
+                let report = match &report {
+                    Some(report) => report,
+                    None => return Err(e),
+                };
 
-        while let Ok(b) = z.read_u64::<LittleEndian>() {
-            let block = crate::pack::vm::unpack(&mut z, BLOCK_SIZE as usize).unwrap();
-            assert!(metadata_block_type(&block[0..]) != BT::UNKNOWN);
-            blocks.push((b, block));
+                eprintln!(
+                    "warning: chunk covering blocks [{}, {}) is damaged ({}), zero-filling",
+                    lo, hi, e
+                );
+                for b in lo..hi {
+                    write_zero_block(w.lock().unwrap().deref_mut(), b)?;
+                }
 
-            if blocks.len() >= 32 {
-                write_blocks(&w, &mut blocks)?;
+                let mut report = report.lock().unwrap();
+                report.chunks_lost += 1;
+                report.blocks_zeroed += hi - lo;
+                report.lost_ranges.push((lo, hi));
             }
         }
+
+        if blocks.len() >= 32 {
+            write_blocks(&w, &mut blocks)?;
+        }
     }
 
     write_blocks(&w, &mut blocks)?;
     Ok(())
 }
 
-pub fn unpack(input_file: &str, output_file: &str) -> Result<(), Box<dyn Error>> {
+// Copies the content of `ref_to` (already materialized by a prior pass)
+// into `b`, now that every self-contained block has been written out.
+fn resolve_references<W>(w: &mut W, pending_refs: &[(u64, u64)]) -> io::Result<()>
+where
+    W: Read + Write + Seek,
+{
+    for &(b, ref_to) in pending_refs {
+        w.seek(io::SeekFrom::Start(ref_to * BLOCK_SIZE))?;
+        let mut block = vec![0; BLOCK_SIZE as usize];
+        w.read_exact(&mut block)?;
+
+        w.seek(io::SeekFrom::Start(b * BLOCK_SIZE))?;
+        w.write_all(&block)?;
+    }
+    Ok(())
+}
+
+// Feeds the plain length-prefixed chunk stream (either read straight
+// off disk, or reassembled from FEC stripes) to the decode workers.
+fn dispatch_chunks<R: Read>(
+    mut body: R,
+    senders: &[std::sync::mpsc::SyncSender<ChunkMsg>],
+) -> io::Result<()> {
+    let nr_jobs = senders.len();
+    let mut next_worker = 0;
+    while let Ok(lo) = body.read_u64::<LittleEndian>() {
+        let hi = body.read_u64::<LittleEndian>()?;
+        let codec = body.read_u8()?;
+        let len = body.read_u64::<LittleEndian>()?;
+        if len > MAX_CHUNK_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "chunk length {} exceeds sanity limit, pack file is corrupt",
+                    len
+                ),
+            ));
+        }
+        let mut bytes = vec![0; len as usize];
+        body.read_exact(&mut bytes)?;
+        // Outside salvage mode a worker exits as soon as it hits a
+        // damaged chunk, closing its receiver; the next chunk routed to
+        // it would otherwise panic this thread instead of letting
+        // `unpack` return the worker's actual error to the caller.
+        if senders[next_worker].send((lo, hi, codec, bytes)).is_err() {
+            break;
+        }
+        next_worker = (next_worker + 1) % nr_jobs;
+    }
+    Ok(())
+}
+
+pub fn unpack(
+    input_file: &str,
+    output_file: &str,
+    expected_parity_shards: Option<u64>,
+    salvage: bool,
+) -> Result<(), Box<dyn Error>> {
     let mut input = OpenOptions::new()
         .read(true)
         .write(false)
         .open(input_file)?;
 
+    // A streaming pack (`pack_stream -o -`) has its own magic and can't
+    // be parsed by `read_header`'s fixed-offset layout; recognise it up
+    // front and route to `unpack_stream` instead of failing with a
+    // confusing "bad magic number" error.
+    if peek_magic(&mut input)? == STREAM_MAGIC {
+        if expected_parity_shards.is_some() {
+            return Err("--parity-shards is not supported for a streaming pack file".into());
+        }
+        if salvage {
+            return Err("--salvage is not supported for a streaming pack file".into());
+        }
+        drop(input);
+        return unpack_stream(input_file, output_file);
+    }
+
+    // `resolve_references` below reads a back-reference's target block
+    // back out of this same file, so it needs read access as well as
+    // write.
     let mut output = OpenOptions::new()
-        .read(false)
+        .read(true)
         .write(true)
         .create(true)
         .truncate(true)
         .open(output_file)?;
 
-    let nr_blocks = read_header(&input)?;
+    let header = read_header(&input)?;
+    let nr_blocks = header.nr_blocks;
+
+    if let Some(expected) = expected_parity_shards {
+        if expected != header.fec_m {
+            return Err(format!(
+                "--parity-shards={} was given, but the pack file was created with {}",
+                expected, header.fec_m
+            )
+            .into());
+        }
+    }
 
     // zero the last block to size the file
     write_zero_block(&mut output, nr_blocks - 1)?;
@@ -322,21 +1191,49 @@ pub fn unpack(input_file: &str, output_file: &str) -> Result<(), Box<dyn Error>>
     let nr_jobs = num_cpus::get();
     let mut senders = Vec::new();
     let mut threads = Vec::new();
+    let pending_refs = Arc::new(Mutex::new(Vec::new()));
+    let report = if salvage {
+        Some(Arc::new(Mutex::new(SalvageReport::default())))
+    } else {
+        None
+    };
 
     for _ in 0..nr_jobs {
         let (tx, rx) = sync_channel(1);
         let output = Arc::clone(&output);
+        let pending_refs = Arc::clone(&pending_refs);
+        let report = report.clone();
         senders.push(tx);
-        threads.push(spawn(move || decode_worker(rx, output)));
+        threads.push(spawn(move || {
+            decode_worker(rx, output, pending_refs, report)
+        }));
     }
 
-    // Read z compressed chunk, and hand to worker thread.
-    let mut next_worker = 0;
-    while let Ok(len) = input.read_u64::<LittleEndian>() {
-        let mut bytes = vec![0; len as usize];
-        input.read_exact(&mut bytes)?;
-        senders[next_worker].send(bytes).unwrap();
-        next_worker = (next_worker + 1) % nr_jobs;
+    if header.fec_m == 0 {
+        dispatch_chunks(&mut input, &senders)?;
+    } else {
+        // Chunk records don't line up with stripe boundaries (a real
+        // compressed chunk stream almost never divides evenly into
+        // fixed `k * shard_size` windows), so the reconstructed stripe
+        // bodies have to be reassembled into one contiguous stream
+        // before `dispatch_chunks` parses it; parsing each stripe on
+        // its own would desync on any record that straddles a
+        // boundary.
+        let mut body = Vec::new();
+        loop {
+            match read_fec_stripe(
+                &mut input,
+                header.fec_k as usize,
+                header.fec_m as usize,
+                header.fec_shard_size as usize,
+                report.as_ref(),
+            ) {
+                Ok(stripe) => body.extend_from_slice(&stripe),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+        dispatch_chunks(Cursor::new(body), &senders)?;
     }
 
     for s in senders {
@@ -346,5 +1243,906 @@ pub fn unpack(input_file: &str, output_file: &str) -> Result<(), Box<dyn Error>>
     for t in threads {
         t.join().unwrap()?;
     }
+
+    // All self-contained blocks are on disk now, so back-references can
+    // be resolved by copying from their already-materialized target.
+    let pending_refs = Arc::try_unwrap(pending_refs).unwrap().into_inner().unwrap();
+    let mut output = Arc::try_unwrap(output).unwrap().into_inner().unwrap();
+
+    // Now that every chunk has been decoded, `lost_ranges` is complete,
+    // so a reference can be checked against it: if its target falls in
+    // a range that got zero-filled, resolving it just copies that zero
+    // data over, and the summary should call it zeroed rather than
+    // recovered.
+    if let Some(report) = &report {
+        let mut report = report.lock().unwrap();
+        let mut newly_lost = Vec::new();
+        for &(b, ref_to) in &pending_refs {
+            let target_lost = report
+                .lost_ranges
+                .iter()
+                .any(|&(lo, hi)| ref_to >= lo && ref_to < hi);
+            if target_lost {
+                report.blocks_zeroed += 1;
+                newly_lost.push((b, b + 1));
+            } else {
+                report.blocks_recovered += 1;
+            }
+        }
+        report.lost_ranges.extend(newly_lost);
+    }
+
+    resolve_references(&mut output, &pending_refs)?;
+
+    if let Some(report) = report {
+        Arc::try_unwrap(report)
+            .unwrap()
+            .into_inner()
+            .unwrap()
+            .print_summary();
+    }
+
+    Ok(())
+}
+
+// Streams the whole pack file and confirms its BLAKE3 digest still
+// matches what was recorded at pack time, without writing anything
+// out.  Lets an operator confirm a dump is intact before relying on it.
+pub fn verify(input_file: &str) -> Result<(), Box<dyn Error>> {
+    let mut input = OpenOptions::new()
+        .read(true)
+        .write(false)
+        .open(input_file)?;
+
+    if peek_magic(&mut input)? == STREAM_MAGIC {
+        return Err(
+            "this is a streaming pack file (created with pack_stream); its trailer digest is \
+             checked automatically by unpack_stream, there is no separate --verify mode for \
+             streaming packs"
+                .into(),
+        );
+    }
+
+    let header = read_header(&mut input)?;
+
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = input.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[0..n]);
+    }
+
+    if hasher.finalize().as_bytes() == &header.digest {
+        println!(
+            "OK: digest matches, {} blocks, fec parity shards = {}, default compressor = {}",
+            header.nr_blocks,
+            header.fec_m,
+            codec_name(header.default_codec)
+        );
+        Ok(())
+    } else {
+        Err("FAILED: digest mismatch, the pack file is truncated or corrupt".into())
+    }
+}
+
+//
+// ---- Streaming pack/unpack for pipes and sockets ----
+//
+// `pack`/`unpack` above need random access: `get_nr_blocks` stats the
+// input up front, `crunch` seeks around to read its chunk ranges, and
+// `patch_body_digest` seeks back into the header once the body is
+// known. None of that works when the input is `dmsetup`'s stdout piped
+// straight in, or the output is piped on to `ssh`. `pack_stream` and
+// `unpack_stream` below are a single-threaded, single-pass alternative
+// for exactly that case: the device is read front-to-back exactly
+// once, and neither the input nor the packed stream is ever seeked
+// (`unpack_stream` does seek its output, the target device). FEC is
+// out of scope here, since a
+// parity stripe needs the whole body staged before it can be written.
+//
+// A streaming pack has its own magic so `read_stream_header` rejects a
+// plain pack file (and vice versa); `nr_blocks` and the digest can't be
+// patched into a fixed header position on an unseekable output, so
+// they're written as a trailer after a sentinel marking the end of the
+// chunk stream instead.
+
+const STREAM_MAGIC: u64 = 0xa537a0aa6309ef78;
+const CHUNK_STREAM_END: u64 = u64::MAX;
+
+fn open_read_stream(path: &str) -> io::Result<Box<dyn Read>> {
+    if path == "-" {
+        Ok(Box::new(io::stdin()))
+    } else {
+        Ok(Box::new(
+            OpenOptions::new().read(true).write(false).open(path)?,
+        ))
+    }
+}
+
+fn open_write_stream(path: &str) -> io::Result<Box<dyn Write>> {
+    if path == "-" {
+        Ok(Box::new(io::stdout()))
+    } else {
+        Ok(Box::new(
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?,
+        ))
+    }
+}
+
+fn write_stream_header<W: Write>(w: &mut W, codec: u8) -> io::Result<()> {
+    w.write_u64::<LittleEndian>(STREAM_MAGIC)?;
+    w.write_u64::<LittleEndian>(PACK_VERSION)?;
+    w.write_u64::<LittleEndian>(4096)?;
+    w.write_u8(codec)?;
+    Ok(())
+}
+
+struct StreamHeader {
+    #[allow(dead_code)]
+    codec: u8,
+}
+
+fn read_stream_header<R: Read>(r: &mut R) -> io::Result<StreamHeader> {
+    let magic = r.read_u64::<LittleEndian>()?;
+    if magic != STREAM_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bad magic number, not a thin_metadata_pack stream (or it's corrupt)",
+        ));
+    }
+    let version = r.read_u64::<LittleEndian>()?;
+    if version != PACK_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "unsupported pack version {} (expected {}), stream is corrupt or from an incompatible build",
+                version, PACK_VERSION
+            ),
+        ));
+    }
+    let block_size = r.read_u64::<LittleEndian>()?;
+    if block_size != 4096 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "unexpected block size {} in stream header, stream is corrupt",
+                block_size
+            ),
+        ));
+    }
+    let codec = r.read_u8()?;
+    Ok(StreamHeader { codec })
+}
+
+// `unpack`/`verify` need random access (seeking back to the start after
+// peeking), so they can't share `open_read_stream`'s pipe-friendly
+// sequential reader. Peeking the magic before committing to
+// `read_header`'s format lets both give a clear "wrong pack format"
+// error (or dispatch to the streaming path) instead of failing deep
+// inside `read_header` with a message that doesn't mention the real
+// cause.
+fn peek_magic<R: Read + Seek>(r: &mut R) -> io::Result<u64> {
+    let magic = r.read_u64::<LittleEndian>()?;
+    r.seek(io::SeekFrom::Start(0))?;
+    Ok(magic)
+}
+
+// Writes one chunk (header + compressed bytes) and folds it into the
+// running digest, since there's no way to seek back and patch a digest
+// in once the stream is done.
+fn write_stream_chunk<W: Write>(
+    w: &mut W,
+    hasher: &mut blake3::Hasher,
+    lo: u64,
+    hi: u64,
+    codec: u8,
+    compressed: &[u8],
+) -> io::Result<()> {
+    let mut header = Vec::new();
+    header.write_u64::<LittleEndian>(lo)?;
+    header.write_u64::<LittleEndian>(hi)?;
+    header.write_u8(codec)?;
+    header.write_u64::<LittleEndian>(compressed.len() as u64)?;
+
+    hasher.update(&header);
+    hasher.update(compressed);
+    w.write_all(&header)?;
+    w.write_all(compressed)?;
+    Ok(())
+}
+
+// Single-pass equivalent of `pack`: reads `input_file` sequentially
+// (stdin if "-"), classifying and deduplicating blocks as they arrive
+// rather than shuffling pre-computed ranges across worker threads. This
+// gives up `crunch`'s parallelism in exchange for working on a pipe.
+pub fn pack_stream(
+    input_file: &str,
+    output_file: &str,
+    codec: u8,
+    level: i32,
+) -> Result<(), Box<dyn Error>> {
+    let mut input = open_read_stream(input_file)?;
+    let mut output = open_write_stream(output_file)?;
+
+    write_stream_header(&mut output, codec)?;
+
+    let mut hasher = blake3::Hasher::new();
+    let mut dedup_table: HashMap<BlockDigest, u64> = HashMap::new();
+    let mut raw = Vec::new();
+    let mut written = 0u64;
+    let mut chunk_lo: Option<u64> = None;
+    let mut chunk_hi = 0u64;
+    let mut nr_blocks = 0u64;
+
+    loop {
+        let mut block = vec![0u8; BLOCK_SIZE as usize];
+        match input.read_exact(&mut block) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(Box::new(e)),
+        }
+        let b = nr_blocks;
+        nr_blocks += 1;
+
+        let kind = metadata_block_type(&block);
+        if kind != BT::UNKNOWN {
+            if chunk_lo.is_none() {
+                chunk_lo = Some(b);
+            }
+            chunk_hi = b + 1;
+
+            raw.write_u64::<LittleEndian>(b)?;
+            let digest = hash_block(&block);
+            match dedup_table.get(&digest) {
+                Some(&earlier) => {
+                    raw.write_u8(RECORD_REFERENCE)?;
+                    raw.write_u64::<LittleEndian>(earlier)?;
+                }
+                None => {
+                    dedup_table.insert(digest, b);
+                    raw.write_u8(RECORD_PACKED)?;
+                    pack_block(&mut raw, kind, &block);
+                }
+            }
+
+            written += 1;
+            if written == 1024 {
+                let compressed = compress_chunk(codec, level, &raw)?;
+                write_stream_chunk(
+                    &mut output,
+                    &mut hasher,
+                    chunk_lo.take().unwrap(),
+                    chunk_hi,
+                    codec,
+                    &compressed,
+                )?;
+                raw.clear();
+                written = 0;
+            }
+        }
+    }
+
+    if written > 0 {
+        let compressed = compress_chunk(codec, level, &raw)?;
+        write_stream_chunk(
+            &mut output,
+            &mut hasher,
+            chunk_lo.take().unwrap(),
+            chunk_hi,
+            codec,
+            &compressed,
+        )?;
+    }
+
+    let mut end = Vec::new();
+    end.write_u64::<LittleEndian>(CHUNK_STREAM_END)?;
+    hasher.update(&end);
+    output.write_all(&end)?;
+
+    output.write_u64::<LittleEndian>(nr_blocks)?;
+    output.write_all(hasher.finalize().as_bytes())?;
+
+    Ok(())
+}
+
+// Single-pass equivalent of `unpack`: reads `input_file` sequentially
+// (stdin if "-") and materializes blocks into `output_file` as records
+// arrive instead of pre-sizing it from a block count known up front.
+// The output still needs to be a seekable path (e.g. the target block
+// device), since references are resolved by reading back an
+// already-materialized block; only the input side needs to tolerate a
+// pipe or socket.
+pub fn unpack_stream(input_file: &str, output_file: &str) -> Result<(), Box<dyn Error>> {
+    let mut input = open_read_stream(input_file)?;
+    read_stream_header(&mut input)?;
+
+    let mut output = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output_file)?;
+
+    let mut hasher = blake3::Hasher::new();
+    let mut pending_refs = Vec::new();
+
+    loop {
+        let lo = input.read_u64::<LittleEndian>()?;
+        if lo == CHUNK_STREAM_END {
+            let mut end = Vec::new();
+            end.write_u64::<LittleEndian>(CHUNK_STREAM_END)?;
+            hasher.update(&end);
+            break;
+        }
+        let hi = input.read_u64::<LittleEndian>()?;
+        let codec = input.read_u8()?;
+        let len = input.read_u64::<LittleEndian>()?;
+        if len > MAX_CHUNK_LEN {
+            return Err(format!(
+                "chunk length {} exceeds sanity limit, pack file is corrupt",
+                len
+            )
+            .into());
+        }
+        let mut bytes = vec![0u8; len as usize];
+        input.read_exact(&mut bytes)?;
+
+        let mut header = Vec::new();
+        header.write_u64::<LittleEndian>(lo)?;
+        header.write_u64::<LittleEndian>(hi)?;
+        header.write_u8(codec)?;
+        header.write_u64::<LittleEndian>(len)?;
+        hasher.update(&header);
+        hasher.update(&bytes);
+
+        let (blocks, refs) = decode_chunk(codec, &bytes)?;
+        for (b, block) in blocks {
+            output.seek(io::SeekFrom::Start(b * BLOCK_SIZE))?;
+            output.write_all(&block)?;
+        }
+        pending_refs.extend(refs);
+    }
+
+    let nr_blocks = input.read_u64::<LittleEndian>()?;
+    let mut digest = [0u8; DIGEST_LEN];
+    input.read_exact(&mut digest)?;
+
+    if hasher.finalize().as_bytes() != &digest {
+        return Err(
+            "FAILED: streamed pack is corrupt, trailer digest does not match the chunk stream"
+                .into(),
+        );
+    }
+
+    // Size the file to match the original device, without touching any
+    // blocks already written by the loop above.
+    if nr_blocks > 0 {
+        output.set_len(nr_blocks * BLOCK_SIZE)?;
+    }
+
+    resolve_references(&mut output, &pending_refs)?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn tmp_path(name: &str) -> std::path::PathBuf {
+        let mut p = std::env::temp_dir();
+        p.push(format!("thinp-pack-test-{}-{}", std::process::id(), name));
+        p
+    }
+
+    fn write_device(path: &std::path::Path, blocks: &[Vec<u8>]) {
+        let mut f = std::fs::File::create(path).unwrap();
+        for b in blocks {
+            f.write_all(b).unwrap();
+        }
+    }
+
+    fn read_whole(path: &std::path::Path) -> Vec<u8> {
+        let mut f = std::fs::File::open(path).unwrap();
+        let mut data = Vec::new();
+        f.read_to_end(&mut data).unwrap();
+        data
+    }
+
+    // A block whose checksum classifies it as a superblock, filled with
+    // cheap xorshift noise so it's both distinguishable from other
+    // synthetic blocks and incompressible enough to exercise codecs and
+    // FEC stripe sizing realistically.
+    fn make_block(seed: u32) -> Vec<u8> {
+        let mut buf = vec![0u8; BLOCK_SIZE as usize];
+        let mut x = seed ^ 0x9e3779b9;
+        for b in buf[4..].iter_mut() {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            *b = x as u8;
+        }
+        let csum = checksum(&buf);
+        let sum_on_disk = csum ^ SUPERBLOCK_CSUM_XOR;
+        buf[0..4].copy_from_slice(&sum_on_disk.to_le_bytes());
+        buf
+    }
+
+    #[test]
+    fn dedup_round_trip_restores_duplicate_blocks() {
+        let input = tmp_path("dedup-in.img");
+        let packed = tmp_path("dedup-packed.bin");
+        let output = tmp_path("dedup-out.img");
+
+        // `pack()` carves the device into `nr_blocks / (nr_jobs * 64)`-ish
+        // ranges (at least 128 blocks each, see `mk_chunk_vecs`) and reads each range
+        // in full, so the device needs to be a whole multiple of 128
+        // blocks or the last range reads past EOF.
+        let nr_blocks = 128usize;
+        let superblock = make_block(1);
+        let zero = vec![0u8; BLOCK_SIZE as usize];
+        let mut blocks = vec![zero.clone(); nr_blocks];
+        blocks[0] = superblock.clone();
+        blocks[nr_blocks - 1] = superblock.clone();
+        write_device(&input, &blocks);
+
+        pack(
+            input.to_str().unwrap(),
+            packed.to_str().unwrap(),
+            0,
+            CODEC_ZLIB,
+            6,
+        )
+        .unwrap();
+        unpack(
+            packed.to_str().unwrap(),
+            output.to_str().unwrap(),
+            None,
+            false,
+        )
+        .unwrap();
+
+        let data = read_whole(&output);
+        let b0 = &data[0..BLOCK_SIZE as usize];
+        let b1 = &data[BLOCK_SIZE as usize..2 * BLOCK_SIZE as usize];
+        let last_start = (nr_blocks - 1) * BLOCK_SIZE as usize;
+        let b_last = &data[last_start..last_start + BLOCK_SIZE as usize];
+
+        assert_eq!(b0, superblock.as_slice());
+        assert_eq!(b1, zero.as_slice());
+        assert_eq!(
+            b_last,
+            superblock.as_slice(),
+            "the back-reference should restore the exact content of the first occurrence"
+        );
+
+        std::fs::remove_file(&input).ok();
+        std::fs::remove_file(&packed).ok();
+        std::fs::remove_file(&output).ok();
+    }
+
+    #[test]
+    fn fec_round_trip_recovers_from_corrupted_shard() {
+        let input = tmp_path("fec-in.img");
+        let packed = tmp_path("fec-packed.bin");
+        let output = tmp_path("fec-out.img");
+
+        // Enough blocks (32MiB raw, mostly-incompressible) to comfortably
+        // span more than one FEC stripe (FEC_K * FEC_SHARD_SIZE = 8MiB
+        // per stripe), so the corruption below reliably lands inside the
+        // second stripe rather than the first.
+        let nr_blocks = 8192usize;
+        let mut blocks = Vec::with_capacity(nr_blocks);
+        for i in 0..nr_blocks {
+            blocks.push(make_block(i as u32));
+        }
+        write_device(&input, &blocks);
+
+        let nr_parity_shards = 2u64;
+        pack(
+            input.to_str().unwrap(),
+            packed.to_str().unwrap(),
+            nr_parity_shards,
+            CODEC_ZLIB,
+            6,
+        )
+        .unwrap();
+
+        // Flip a byte well inside the first data shard of the second
+        // stripe, past that shard's own CRC, so the damage is only
+        // detected (and repaired) via the parity shards. Each stripe on
+        // disk is an 8-byte "take" length followed by (k+m) shards, each
+        // a 4-byte CRC plus `shard_size` bytes.
+        let mut data = read_whole(&packed);
+        let header_len = HEADER_LEN as usize;
+        let shard_size = FEC_SHARD_SIZE;
+        let nr_shards_per_stripe = FEC_K + nr_parity_shards as usize;
+        let stripe_record_size = 8 + nr_shards_per_stripe * (4 + shard_size);
+        let corrupt_at = header_len + stripe_record_size + 8 + 4 + shard_size / 2;
+        data[corrupt_at] ^= 0xff;
+        std::fs::write(&packed, &data).unwrap();
+
+        unpack(
+            packed.to_str().unwrap(),
+            output.to_str().unwrap(),
+            Some(nr_parity_shards),
+            false,
+        )
+        .unwrap();
+
+        let restored = read_whole(&output);
+        let original: Vec<u8> = blocks.into_iter().flatten().collect();
+        assert_eq!(restored, original);
+
+        std::fs::remove_file(&input).ok();
+        std::fs::remove_file(&packed).ok();
+        std::fs::remove_file(&output).ok();
+    }
+
+    #[test]
+    fn unpack_rejects_a_corrupted_fec_shard_size() {
+        let input = tmp_path("fec-bad-header-in.img");
+        let packed = tmp_path("fec-bad-header-packed.bin");
+        let output = tmp_path("fec-bad-header-out.img");
+
+        let nr_blocks = 16usize;
+        let mut blocks = Vec::with_capacity(nr_blocks);
+        for i in 0..nr_blocks {
+            blocks.push(make_block(i as u32));
+        }
+        write_device(&input, &blocks);
+
+        pack(input.to_str().unwrap(), packed.to_str().unwrap(), 2, CODEC_ZLIB, 6).unwrap();
+
+        // Corrupt the on-disk fec_shard_size field (the 7th header u64,
+        // right after nr_blocks, fec_k and fec_m) to a huge value. Without
+        // a bounds check in `read_header`, `read_fec_stripe` would take
+        // this on faith and try to allocate that many bytes per shard.
+        let mut data = read_whole(&packed);
+        let fec_shard_size_offset = 6 * 8;
+        data[fec_shard_size_offset..fec_shard_size_offset + 8]
+            .copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+        std::fs::write(&packed, &data).unwrap();
+
+        let err = unpack(
+            packed.to_str().unwrap(),
+            output.to_str().unwrap(),
+            Some(2),
+            false,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("fec_shard_size"));
+
+        std::fs::remove_file(&input).ok();
+        std::fs::remove_file(&packed).ok();
+    }
+
+    #[test]
+    fn verify_detects_corrupted_body() {
+        let input = tmp_path("verify-in.img");
+        let packed = tmp_path("verify-packed.bin");
+
+        let nr_blocks = 128usize;
+        let mut blocks = Vec::with_capacity(nr_blocks);
+        for i in 0..nr_blocks {
+            blocks.push(make_block(i as u32));
+        }
+        write_device(&input, &blocks);
+
+        pack(
+            input.to_str().unwrap(),
+            packed.to_str().unwrap(),
+            0,
+            CODEC_ZLIB,
+            6,
+        )
+        .unwrap();
+
+        assert!(verify(packed.to_str().unwrap()).is_ok());
+
+        let mut data = read_whole(&packed);
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(&packed, &data).unwrap();
+
+        assert!(verify(packed.to_str().unwrap()).is_err());
+
+        std::fs::remove_file(&input).ok();
+        std::fs::remove_file(&packed).ok();
+    }
+
+    #[test]
+    fn salvage_round_trip_zero_fills_damaged_chunk() {
+        let input = tmp_path("salvage-in.img");
+        let packed = tmp_path("salvage-packed.bin");
+        let output = tmp_path("salvage-out.img");
+
+        let nr_blocks = 2048usize;
+        let mut blocks = Vec::with_capacity(nr_blocks);
+        for i in 0..nr_blocks {
+            blocks.push(make_block(i as u32));
+        }
+        write_device(&input, &blocks);
+
+        pack(
+            input.to_str().unwrap(),
+            packed.to_str().unwrap(),
+            0,
+            CODEC_ZLIB,
+            6,
+        )
+        .unwrap();
+
+        // Read back the first chunk record's own (lo, hi) rather than
+        // assuming a fixed layout: ranges are shuffled across workers
+        // before packing (see `mk_chunk_vecs`), so which blocks land in
+        // the first chunk isn't predictable.
+        let mut data = read_whole(&packed);
+        let mut hdr = Cursor::new(&data[HEADER_LEN as usize..]);
+        let lo = hdr.read_u64::<LittleEndian>().unwrap();
+        let hi = hdr.read_u64::<LittleEndian>().unwrap();
+        let _codec = hdr.read_u8().unwrap();
+        let len = hdr.read_u64::<LittleEndian>().unwrap();
+        let body_start = HEADER_LEN as usize + 8 + 8 + 1 + 8;
+        data[body_start + (len as usize / 2)] ^= 0xff;
+        std::fs::write(&packed, &data).unwrap();
+
+        assert!(unpack(
+            packed.to_str().unwrap(),
+            output.to_str().unwrap(),
+            None,
+            false,
+        )
+        .is_err());
+
+        unpack(
+            packed.to_str().unwrap(),
+            output.to_str().unwrap(),
+            None,
+            true,
+        )
+        .unwrap();
+
+        let restored = read_whole(&output);
+        let safe_block = if hi < nr_blocks as u64 { hi } else { 0 };
+        assert!(
+            safe_block < lo || safe_block >= hi,
+            "safe_block must fall outside the damaged chunk's range"
+        );
+        let start = safe_block as usize * BLOCK_SIZE as usize;
+        let end = start + BLOCK_SIZE as usize;
+        assert_eq!(
+            &restored[start..end],
+            blocks[safe_block as usize].as_slice()
+        );
+
+        std::fs::remove_file(&input).ok();
+        std::fs::remove_file(&packed).ok();
+        std::fs::remove_file(&output).ok();
+    }
+
+    #[test]
+    fn mixed_codec_chunks_both_decode() {
+        let packed = tmp_path("mixed-codec-packed.bin");
+        let output = tmp_path("mixed-codec-out.img");
+
+        let block0 = make_block(1);
+        let block1 = make_block(2);
+
+        let mut raw0 = Vec::new();
+        raw0.write_u64::<LittleEndian>(0).unwrap();
+        raw0.write_u8(RECORD_PACKED).unwrap();
+        pack_block(&mut raw0, metadata_block_type(&block0), &block0);
+        let zlib_body = compress_chunk(CODEC_ZLIB, 6, &raw0).unwrap();
+
+        let mut raw1 = Vec::new();
+        raw1.write_u64::<LittleEndian>(1).unwrap();
+        raw1.write_u8(RECORD_PACKED).unwrap();
+        pack_block(&mut raw1, metadata_block_type(&block1), &block1);
+        let zstd_body = compress_chunk(CODEC_ZSTD, 6, &raw1).unwrap();
+
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&packed)
+            .unwrap();
+        write_header(&f, 2, 0, 0, 0, CODEC_ZLIB).unwrap();
+        f.write_u64::<LittleEndian>(0).unwrap();
+        f.write_u64::<LittleEndian>(1).unwrap();
+        f.write_u8(CODEC_ZLIB).unwrap();
+        f.write_u64::<LittleEndian>(zlib_body.len() as u64).unwrap();
+        f.write_all(&zlib_body).unwrap();
+        f.write_u64::<LittleEndian>(1).unwrap();
+        f.write_u64::<LittleEndian>(2).unwrap();
+        f.write_u8(CODEC_ZSTD).unwrap();
+        f.write_u64::<LittleEndian>(zstd_body.len() as u64).unwrap();
+        f.write_all(&zstd_body).unwrap();
+        patch_body_digest(&mut f).unwrap();
+        drop(f);
+
+        unpack(
+            packed.to_str().unwrap(),
+            output.to_str().unwrap(),
+            None,
+            false,
+        )
+        .unwrap();
+
+        let restored = read_whole(&output);
+        assert_eq!(&restored[0..BLOCK_SIZE as usize], block0.as_slice());
+        assert_eq!(
+            &restored[BLOCK_SIZE as usize..2 * BLOCK_SIZE as usize],
+            block1.as_slice()
+        );
+
+        std::fs::remove_file(&packed).ok();
+        std::fs::remove_file(&output).ok();
+    }
+
+    #[test]
+    fn zstd_round_trip_restores_blocks() {
+        let input = tmp_path("zstd-in.img");
+        let packed = tmp_path("zstd-packed.bin");
+        let output = tmp_path("zstd-out.img");
+
+        let nr_blocks = 128usize;
+        let superblock = make_block(1);
+        let mut blocks = vec![vec![0u8; BLOCK_SIZE as usize]; nr_blocks];
+        blocks[0] = superblock.clone();
+        write_device(&input, &blocks);
+
+        pack(
+            input.to_str().unwrap(),
+            packed.to_str().unwrap(),
+            0,
+            CODEC_ZSTD,
+            3,
+        )
+        .unwrap();
+        unpack(
+            packed.to_str().unwrap(),
+            output.to_str().unwrap(),
+            None,
+            false,
+        )
+        .unwrap();
+
+        let data = read_whole(&output);
+        assert_eq!(&data[0..BLOCK_SIZE as usize], superblock.as_slice());
+
+        std::fs::remove_file(&input).ok();
+        std::fs::remove_file(&packed).ok();
+        std::fs::remove_file(&output).ok();
+    }
+
+    #[test]
+    fn unpack_rejects_an_unknown_codec_tag() {
+        let packed = tmp_path("bad-codec-packed.bin");
+        let output = tmp_path("bad-codec-out.img");
+
+        let block0 = make_block(1);
+        let mut raw = Vec::new();
+        raw.write_u64::<LittleEndian>(0).unwrap();
+        raw.write_u8(RECORD_PACKED).unwrap();
+        pack_block(&mut raw, metadata_block_type(&block0), &block0);
+        let compressed = compress_chunk(CODEC_ZLIB, 6, &raw).unwrap();
+
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&packed)
+            .unwrap();
+        write_header(&f, 1, 0, 0, 0, CODEC_ZLIB).unwrap();
+        f.write_u64::<LittleEndian>(0).unwrap();
+        f.write_u64::<LittleEndian>(1).unwrap();
+        f.write_u8(0xee).unwrap(); // not a valid codec tag
+        f.write_u64::<LittleEndian>(compressed.len() as u64)
+            .unwrap();
+        f.write_all(&compressed).unwrap();
+        patch_body_digest(&mut f).unwrap();
+        drop(f);
+
+        let err = unpack(
+            packed.to_str().unwrap(),
+            output.to_str().unwrap(),
+            None,
+            false,
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("unknown chunk codec"),
+            "unexpected error: {}",
+            err
+        );
+
+        std::fs::remove_file(&packed).ok();
+        std::fs::remove_file(&output).ok();
+    }
+
+    #[test]
+    fn streaming_round_trip_restores_duplicate_blocks() {
+        let input = tmp_path("stream-in.img");
+        let packed = tmp_path("stream-packed.bin");
+        let output = tmp_path("stream-out.img");
+
+        let superblock = make_block(1);
+        let zero = vec![0u8; BLOCK_SIZE as usize];
+        let blocks = vec![superblock.clone(), zero.clone(), superblock.clone()];
+        write_device(&input, &blocks);
+
+        pack_stream(
+            input.to_str().unwrap(),
+            packed.to_str().unwrap(),
+            CODEC_ZLIB,
+            6,
+        )
+        .unwrap();
+        unpack_stream(packed.to_str().unwrap(), output.to_str().unwrap()).unwrap();
+
+        let data = read_whole(&output);
+        let b0 = &data[0..BLOCK_SIZE as usize];
+        let b1 = &data[BLOCK_SIZE as usize..2 * BLOCK_SIZE as usize];
+        let b2 = &data[2 * BLOCK_SIZE as usize..3 * BLOCK_SIZE as usize];
+
+        assert_eq!(b0, superblock.as_slice());
+        assert_eq!(b1, zero.as_slice());
+        assert_eq!(
+            b2,
+            superblock.as_slice(),
+            "the back-reference should restore the exact content of the first occurrence"
+        );
+
+        std::fs::remove_file(&input).ok();
+        std::fs::remove_file(&packed).ok();
+        std::fs::remove_file(&output).ok();
+    }
+
+    #[test]
+    fn stream_unpack_detects_a_trailer_digest_mismatch() {
+        let input = tmp_path("stream-corrupt-in.img");
+        let packed = tmp_path("stream-corrupt-packed.bin");
+        let output = tmp_path("stream-corrupt-out.img");
+
+        let blocks = vec![make_block(1), make_block(2)];
+        write_device(&input, &blocks);
+
+        pack_stream(
+            input.to_str().unwrap(),
+            packed.to_str().unwrap(),
+            CODEC_ZLIB,
+            6,
+        )
+        .unwrap();
+
+        // Flip the last byte of the trailer digest itself, so the chunk
+        // stream it was computed over still decodes cleanly and only the
+        // final comparison against the recorded digest catches it.
+        let mut data = read_whole(&packed);
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(&packed, &data).unwrap();
+
+        let err = unpack_stream(packed.to_str().unwrap(), output.to_str().unwrap()).unwrap_err();
+        assert!(
+            err.to_string().contains("trailer digest"),
+            "unexpected error: {}",
+            err
+        );
+
+        std::fs::remove_file(&input).ok();
+        std::fs::remove_file(&packed).ok();
+        std::fs::remove_file(&output).ok();
+    }
+}